@@ -27,8 +27,56 @@ pub struct Feed {
     pub error_count: u32,
     pub subscribers: HashSet<SubscriberID>,
     hash_list: Vec<u64>,
+    #[serde(default)]
+    pub validators: feed::Validators,
+    // some feeds use the fragment as the identity of items
+    #[serde(default)]
+    pub keep_fragment: bool,
+    // ids and links seen on their own, only to tell which one matched
+    #[serde(default)]
+    seen_ids: Vec<u64>,
+    #[serde(default)]
+    seen_links: Vec<u64>,
 }
 
+impl Feed {
+    // whether an item is new is decided by `hash_list` alone,
+    // the reason only tells which key matched
+    fn seen_by(&self, item: &feed::Item) -> Option<SkipReason> {
+        if !self.hash_list.contains(&gen_item_hash(item, self.keep_fragment)) &&
+            !self.hash_list.contains(&legacy_item_hash(item))
+        {
+            return None;
+        }
+        if let Some(ref id) = item.id {
+            if self.seen_ids.contains(&get_hash(id)) {
+                return Some(SkipReason::Id);
+            }
+        } else if let Some(hash) = link_hash(item, self.keep_fragment) {
+            if self.seen_links.contains(&hash) {
+                return Some(SkipReason::Link);
+            }
+        }
+        // recorded before `seen_ids` and `seen_links` existed
+        Some(SkipReason::Hash)
+    }
+}
+
+// why `Database::dry_run` wouldn't notify an item, by the key that matched
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SkipReason {
+    Id,
+    // title + link without an id, and the link was seen
+    Link,
+    // title only, or a hash from before ids and links were tracked
+    Hash,
+}
+
+#[derive(Debug, Default)]
+pub struct DryRunReport {
+    pub new: Vec<feed::Item>,
+    pub skipped: Vec<(feed::Item, SkipReason)>,
+}
 
 #[derive(Debug)]
 struct DatabaseInner {
@@ -103,8 +151,18 @@ impl DatabaseInner {
                     link: rss_link.to_owned(),
                     title: rss.title.to_owned(),
                     error_count: 0,
-                    hash_list: rss.items.iter().map(gen_item_hash).collect(),
+                    hash_list: rss.items
+                        .iter()
+                        .map(|item| gen_item_hash(item, false))
+                        .collect(),
                     subscribers: HashSet::new(),
+                    validators: Default::default(),
+                    keep_fragment: false,
+                    seen_ids: rss.items
+                        .iter()
+                        .filter_map(|item| item.id.as_ref().map(get_hash))
+                        .collect(),
+                    seen_links: rss.items.iter().filter_map(|item| link_hash(item, false)).collect(),
                 }
             });
             feed.subscribers.insert(subscriber);
@@ -148,6 +206,26 @@ impl DatabaseInner {
         Ok(result)
     }
 
+    // unsubscribes everyone, the feed is gone for good
+    fn remove_feed(&mut self, rss_link: &str) -> Option<Feed> {
+        let feed_id = get_hash(&rss_link);
+        let feed = self.feeds.remove(&feed_id)?;
+        for subscriber in &feed.subscribers {
+            let clear_subscriber = self.subscribers
+                .get_mut(subscriber)
+                .map(|feeds| {
+                    feeds.remove(&feed_id);
+                    feeds.is_empty()
+                })
+                .unwrap_or(false);
+            if clear_subscriber {
+                self.subscribers.remove(subscriber);
+            }
+        }
+        self.save().unwrap_or_default();
+        Some(feed)
+    }
+
     fn delete_subscriber(&mut self, subscriber: SubscriberID) {
         self.get_subscribed_feeds(subscriber)
             .map(|feeds| for feed in feeds {
@@ -166,11 +244,15 @@ impl DatabaseInner {
 
         let mut result = Vec::new();
         let mut new_hash_list = Vec::new();
+        let mut new_ids = Vec::new();
+        let mut new_links = Vec::new();
         let items_len = items.len();
+        let keep_fragment = self.feeds[&feed_id].keep_fragment;
         for item in items {
-            let hash = gen_item_hash(&item);
-            if !self.feeds[&feed_id].hash_list.contains(&hash) {
-                new_hash_list.push(hash);
+            if self.feeds[&feed_id].seen_by(&item).is_none() {
+                new_hash_list.push(gen_item_hash(&item, keep_fragment));
+                new_ids.extend(item.id.as_ref().map(get_hash));
+                new_links.extend(link_hash(&item, keep_fragment));
                 result.push(item);
             }
         }
@@ -178,19 +260,28 @@ impl DatabaseInner {
             {
                 let max_size = items_len * 2;
                 let feed = self.feeds.get_mut(&feed_id).unwrap();
-                let mut append: Vec<u64> = feed.hash_list
-                    .iter()
-                    .take(max_size - new_hash_list.len())
-                    .cloned()
-                    .collect();
-                new_hash_list.append(&mut append);
-                feed.hash_list = new_hash_list;
+                feed.hash_list = keep_recent(new_hash_list, &feed.hash_list, max_size);
+                feed.seen_ids = keep_recent(new_ids, &feed.seen_ids, max_size);
+                feed.seen_links = keep_recent(new_links, &feed.seen_links, max_size);
             }
             self.save().unwrap_or_default();
         }
         result
     }
 
+    // same as `update`, but changes nothing
+    fn dry_run(&self, rss_link: &str, items: Vec<feed::Item>) -> Option<DryRunReport> {
+        let feed = self.feeds.get(&get_hash(&rss_link))?;
+        let mut report = DryRunReport::default();
+        for item in items {
+            match feed.seen_by(&item) {
+                Some(reason) => report.skipped.push((item, reason)),
+                None => report.new.push(item),
+            }
+        }
+        Some(report)
+    }
+
     fn update_title(&mut self, rss_link: &str, new_title: &str) {
         let feed_id = get_hash(&rss_link);
         self.feeds
@@ -199,6 +290,25 @@ impl DatabaseInner {
             .unwrap_or_default();
     }
 
+    fn update_validators(&mut self, rss_link: &str, validators: feed::Validators) {
+        let feed_id = get_hash(&rss_link);
+        self.feeds
+            .get_mut(&feed_id)
+            .map(|feed| feed.validators = validators)
+            .unwrap_or_default();
+    }
+
+    // items already seen may be sent once more, their hashes were recorded
+    // with the other setting
+    fn set_keep_fragment(&mut self, rss_link: &str, keep_fragment: bool) -> Result<()> {
+        let feed_id = get_hash(&rss_link);
+        match self.feeds.get_mut(&feed_id) {
+            Some(feed) => feed.keep_fragment = keep_fragment,
+            None => return Err(ErrorKind::NotSubscribed.into()),
+        }
+        self.save()
+    }
+
     fn save(&self) -> Result<()> {
         let feeds_list: Vec<&Feed> = self.feeds.iter().map(|(_id, feed)| feed).collect();
         let mut file = File::create(&self.path).chain_err(|| {
@@ -223,14 +333,33 @@ impl Clone for Database {
     }
 }
 
-fn gen_item_hash(item: &feed::Item) -> u64 {
-    item.id.as_ref().map(|id| get_hash(&id)).unwrap_or_else(|| {
-        let title = item.title.as_ref().map(|s| s.as_str()).unwrap_or_default();
-        let link = item.link.as_ref().map(|s| s.as_str()).unwrap_or_default();
-        get_hash(&format!("{}{}", title, link))
+fn gen_item_hash(item: &feed::Item, keep_fragment: bool) -> u64 {
+    get_hash(&item.dedup_key(!keep_fragment))
+}
+
+// hashes recorded before fragments were stripped have the whole link
+fn legacy_item_hash(item: &feed::Item) -> u64 {
+    get_hash(&item.dedup_key(false))
+}
+
+fn link_hash(item: &feed::Item, keep_fragment: bool) -> Option<u64> {
+    item.link.as_ref().map(|link| {
+        let link = link.trim();
+        if keep_fragment {
+            get_hash(&link)
+        } else {
+            get_hash(&link.split('#').next().unwrap_or_default())
+        }
     })
 }
 
+// the newest first, up to `max_size`
+fn keep_recent(mut new: Vec<u64>, old: &[u64], max_size: usize) -> Vec<u64> {
+    let rest = max_size.saturating_sub(new.len());
+    new.extend(old.iter().take(rest).cloned());
+    new
+}
+
 impl Database {
     pub fn create(path: &str) -> Result<Database> {
         let feeds: HashMap<FeedID, Feed> = HashMap::new();
@@ -325,15 +454,181 @@ impl Database {
         self.inner.borrow_mut().delete_subscriber(subscriber);
     }
 
+    pub fn remove_feed(&self, rss_link: &str) -> Option<Feed> {
+        self.inner.borrow_mut().remove_feed(rss_link)
+    }
+
     pub fn update(&self, rss_link: &str, items: Vec<feed::Item>) -> Vec<feed::Item> {
         self.inner.borrow_mut().update(rss_link, items)
     }
 
+    pub fn dry_run(&self, rss_link: &str, items: Vec<feed::Item>) -> Option<DryRunReport> {
+        self.inner.borrow().dry_run(rss_link, items)
+    }
+
     pub fn update_title(&self, rss_link: &str, new_title: &str) {
         self.inner.borrow_mut().update_title(rss_link, new_title)
     }
 
+    pub fn update_validators(&self, rss_link: &str, validators: feed::Validators) {
+        self.inner.borrow_mut().update_validators(rss_link, validators)
+    }
+
+    pub fn set_keep_fragment(&self, rss_link: &str, keep_fragment: bool) -> Result<()> {
+        self.inner.borrow_mut().set_keep_fragment(rss_link, keep_fragment)
+    }
+
     fn save(&self) -> Result<()> {
         self.inner.borrow().save()
     }
 }
+
+#[test]
+fn test_dry_run() {
+    let item = |title: &str, link: Option<&str>, id: Option<&str>| {
+        feed::Item {
+            title: Some(title.to_owned()),
+            link: link.map(|s| s.to_owned()),
+            id: id.map(|s| s.to_owned()),
+            ..Default::default()
+        }
+    };
+    let path = std::env::temp_dir().join(format!("rssbot-test-{}.json", std::process::id()));
+    let db = Database::create(path.to_str().unwrap()).unwrap();
+    let rss = feed::RSS {
+        items: vec![
+            item("a", Some("http://example.com/a"), Some("1")),
+            item("b", Some("http://example.com/b"), None),
+            item("c", None, None),
+        ],
+        ..Default::default()
+    };
+    db.subscribe(1, "http://example.com/feed", &rss).unwrap();
+    assert!(db.dry_run("http://example.com/other", Vec::new()).is_none());
+
+    let mut items = rss.items.clone();
+    items.push(item("d", Some("http://example.com/d"), Some("4")));
+    let report = db.dry_run("http://example.com/feed", items).unwrap();
+    let new: Vec<_> = report.new.iter().map(|item| item.title.clone().unwrap()).collect();
+    assert_eq!(new, vec!["d"]);
+    let skipped: Vec<_> = report.skipped
+        .iter()
+        .map(|&(ref item, reason)| (item.title.clone().unwrap(), reason))
+        .collect();
+    assert_eq!(
+        skipped,
+        vec![
+            ("a".to_owned(), SkipReason::Id),
+            ("b".to_owned(), SkipReason::Link),
+            ("c".to_owned(), SkipReason::Hash),
+        ]
+    );
+    // nothing was recorded
+    assert_eq!(db.update("http://example.com/feed", report.new).len(), 1);
+    let _ = std::fs::remove_file(&path);
+}
+
+#[test]
+fn test_dry_run_skip_by_link() {
+    let item = |title: &str, link: &str, id: Option<&str>| {
+        feed::Item {
+            title: Some(title.to_owned()),
+            link: Some(link.to_owned()),
+            id: id.map(|s| s.to_owned()),
+            ..Default::default()
+        }
+    };
+    let path = std::env::temp_dir().join(format!("rssbot-test-link-{}.json", std::process::id()));
+    let db = Database::create(path.to_str().unwrap()).unwrap();
+    let rss = feed::RSS {
+        items: vec![
+            item("a", "http://example.com/a", Some("1500000000-a")),
+            item("b", "http://example.com/b", None),
+        ],
+        ..Default::default()
+    };
+    db.subscribe(1, "http://example.com/feed", &rss).unwrap();
+
+    let items = vec![
+        // a new id is a new item, even with a link seen before
+        item("a", "http://example.com/a", Some("1500000300-a")),
+        item("b", "http://example.com/b#comments", None),
+    ];
+    let report = db.dry_run("http://example.com/feed", items.clone()).unwrap();
+    let skipped: Vec<_> = report.skipped
+        .iter()
+        .map(|&(ref item, reason)| (item.title.clone().unwrap(), reason))
+        .collect();
+    assert_eq!(skipped, vec![("b".to_owned(), SkipReason::Link)]);
+    let new: Vec<_> = report.new.iter().map(|item| item.title.clone().unwrap()).collect();
+    assert_eq!(new, vec!["a"]);
+
+    // `update` agrees with the dry run
+    let updated: Vec<_> = db.update("http://example.com/feed", items)
+        .into_iter()
+        .map(|item| item.title.unwrap())
+        .collect();
+    assert_eq!(updated, vec!["a"]);
+    let _ = std::fs::remove_file(&path);
+}
+
+#[test]
+fn test_seen_by_legacy_hash() {
+    let item = |title: Option<&str>, link: &str| {
+        feed::Item {
+            title: title.map(|s| s.to_owned()),
+            link: Some(link.to_owned()),
+            ..Default::default()
+        }
+    };
+    // as written before fragment stripping, without `seen_ids` and `seen_links`
+    let feed = Feed {
+        link: "http://example.com/feed".to_owned(),
+        title: "t".to_owned(),
+        error_count: 0,
+        subscribers: HashSet::new(),
+        hash_list: vec![
+            get_hash(&"ahttp://example.com/a#comments"),
+            get_hash(&"http://example.com/b"),
+        ],
+        validators: Default::default(),
+        keep_fragment: false,
+        seen_ids: Vec::new(),
+        seen_links: Vec::new(),
+    };
+    assert_eq!(
+        feed.seen_by(&item(Some("a"), "http://example.com/a#comments")),
+        Some(SkipReason::Hash)
+    );
+    assert_eq!(feed.seen_by(&item(None, "http://example.com/b")), Some(SkipReason::Hash));
+    assert_eq!(feed.seen_by(&item(None, "http://example.com/c")), None);
+}
+
+#[test]
+fn test_set_keep_fragment() {
+    let item = |link: &str| {
+        feed::Item {
+            title: Some("t".to_owned()),
+            link: Some(link.to_owned()),
+            ..Default::default()
+        }
+    };
+    let path = std::env::temp_dir().join(format!("rssbot-test-fragment-{}.json", std::process::id()));
+    let db = Database::create(path.to_str().unwrap()).unwrap();
+    let rss = feed::RSS {
+        items: vec![item("http://example.com/#1")],
+        ..Default::default()
+    };
+    db.subscribe(1, "http://example.com/feed", &rss).unwrap();
+    assert!(db.set_keep_fragment("http://example.com/other", true).is_err());
+
+    let items = vec![item("http://example.com/#2")];
+    assert!(db.dry_run("http://example.com/feed", items.clone()).unwrap().new.is_empty());
+    db.set_keep_fragment("http://example.com/feed", true).unwrap();
+    assert_eq!(db.update("http://example.com/feed", items.clone()).len(), 1);
+    assert!(db.update("http://example.com/feed", items).is_empty());
+    // saved along with the feed
+    let db = Database::open(path.to_str().unwrap()).unwrap();
+    assert!(db.get_all_feeds()[0].keep_fragment);
+    let _ = std::fs::remove_file(&path);
+}
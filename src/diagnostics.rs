@@ -0,0 +1,260 @@
+use std::collections::HashSet;
+
+use futures::Future;
+use tokio_curl::Session;
+use quick_xml::events::Event as XmlEvent;
+use quick_xml::reader::Reader as XmlReader;
+
+use errors::*;
+use feed;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FeedFormat {
+    Rss,
+    Atom,
+    Rdf,
+    Unknown,
+}
+
+// the `usize` is the index of the item
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Problem {
+    NoItems,
+    EmptyTitle(usize),
+    MissingLink(usize),
+    RelativeLink(usize),
+    MissingGuid(usize),
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FeedReport {
+    pub format: FeedFormat,
+    pub charset: String,
+    pub item_count: usize,
+    pub items_with_link: usize,
+    pub items_with_id: usize,
+    pub items_with_date: usize,
+    pub problems: Vec<Problem>,
+    pub error: Option<String>,
+}
+
+impl Default for FeedReport {
+    fn default() -> FeedReport {
+        FeedReport {
+            format: FeedFormat::Unknown,
+            charset: "UTF-8".to_owned(),
+            item_count: 0,
+            items_with_link: 0,
+            items_with_id: 0,
+            items_with_date: 0,
+            problems: Vec::new(),
+            error: None,
+        }
+    }
+}
+
+pub fn validate_feed<'a>(
+    session: &Session,
+    link: &str,
+) -> impl Future<Item = FeedReport, Error = Error> + 'a {
+    feed::fetch_raw(session, link, &feed::FetchOptions::default()).map(|resp| validate(&resp.body))
+}
+
+pub fn validate(body: &[u8]) -> FeedReport {
+    let mut report = FeedReport::default();
+    scan(body, &mut report);
+
+    let rss = match feed::parse(body) {
+        Ok(rss) => rss,
+        Err(e) => {
+            if report.error.is_none() {
+                report.error = Some(e.to_string());
+            }
+            return report;
+        }
+    };
+    report.item_count = rss.len();
+    if rss.is_empty() {
+        report.problems.push(Problem::NoItems);
+    }
+    for (i, item) in rss.items.iter().enumerate() {
+        if item.title.as_ref().map_or(true, |t| t.trim().is_empty()) {
+            report.problems.push(Problem::EmptyTitle(i));
+        }
+        match item.link {
+            Some(ref link) => {
+                report.items_with_link += 1;
+                if !link.starts_with("http://") && !link.starts_with("https://") {
+                    report.problems.push(Problem::RelativeLink(i));
+                }
+            }
+            None => report.problems.push(Problem::MissingLink(i)),
+        }
+        if item.id.is_some() {
+            report.items_with_id += 1;
+        } else {
+            report.problems.push(Problem::MissingGuid(i));
+        }
+    }
+    report
+}
+
+// collects what `feed::parse` doesn't keep: the declared charset,
+// the root element and the date elements of items
+fn scan(body: &[u8], report: &mut FeedReport) {
+    let mut reader = XmlReader::from_reader(body);
+    reader.trim_text(true);
+    let mut buf = Vec::new();
+    let mut in_item = false;
+    let mut item_has_date = false;
+    loop {
+        match reader.read_event(&mut buf) {
+            Ok(XmlEvent::Decl(ref e)) => {
+                if let Some(Ok(encoding)) = e.encoding() {
+                    report.charset = reader.decode(&encoding).into_owned();
+                }
+            }
+            Ok(XmlEvent::Start(ref e)) => {
+                let name = reader.decode(e.name());
+                if report.format == FeedFormat::Unknown {
+                    report.format = match name.as_ref() {
+                        "rss" => FeedFormat::Rss,
+                        "feed" => FeedFormat::Atom,
+                        "rdf:RDF" => FeedFormat::Rdf,
+                        _ => FeedFormat::Unknown,
+                    };
+                }
+                match name.as_ref() {
+                    "item" | "entry" => {
+                        in_item = true;
+                        item_has_date = false;
+                    }
+                    "pubDate" | "published" | "updated" | "dc:date" if in_item => {
+                        item_has_date = true;
+                    }
+                    _ => (),
+                }
+            }
+            Ok(XmlEvent::End(ref e)) => {
+                match reader.decode(e.name()).as_ref() {
+                    "item" | "entry" if in_item => {
+                        in_item = false;
+                        if item_has_date {
+                            report.items_with_date += 1;
+                        }
+                    }
+                    _ => (),
+                }
+            }
+            Ok(XmlEvent::Eof) => break,
+            Err(e) => {
+                report.error = Some(e.to_string());
+                break;
+            }
+            _ => (),
+        }
+        buf.clear();
+    }
+}
+
+// Some feeds regenerate the guids on every request, so every item looks new.
+// Detected by comparing two consecutive fetches: no guid in common, but
+// most of the items are still there by title + link.
+// the fetcher keeps no previous fetch to compare with, and no per-feed
+// switch to link-based dedup yet
+#[allow(dead_code)]
+pub fn detect_unstable_ids(prev: &feed::RSS, curr: &feed::RSS) -> bool {
+    let prev_ids: HashSet<&str> = prev.items
+        .iter()
+        .filter_map(|item| item.id.as_ref().map(|s| s.as_str()))
+        .collect();
+    let curr_ids: Vec<&str> = curr.items
+        .iter()
+        .filter_map(|item| item.id.as_ref().map(|s| s.as_str()))
+        .collect();
+    if prev_ids.is_empty() || curr_ids.is_empty() ||
+        curr_ids.iter().any(|id| prev_ids.contains(id))
+    {
+        return false;
+    }
+
+    let content_key = |item: &feed::Item| (item.title.clone(), item.link.clone());
+    let prev_contents: HashSet<_> = prev.items.iter().map(&content_key).collect();
+    let overlap = curr.items
+        .iter()
+        .filter(|&item| {
+            (item.title.is_some() || item.link.is_some()) &&
+                prev_contents.contains(&content_key(item))
+        })
+        .count();
+    overlap * 5 >= curr.len() * 4
+}
+
+#[test]
+fn test_validate() {
+    let report = validate(
+        br#"<?xml version="1.0" encoding="GBK"?>
+        <rss><channel>
+        <title>t</title>
+        <item><title>a</title><link>http://example.com/a</link><guid>a</guid>
+            <pubDate>Sun, 01 Jan 2017 00:00:00 GMT</pubDate></item>
+        <item><title></title><link>/b</link></item>
+        </channel></rss>"#,
+    );
+    assert_eq!(report.format, FeedFormat::Rss);
+    assert_eq!(report.charset, "GBK");
+    assert_eq!(report.item_count, 2);
+    assert_eq!(report.items_with_link, 2);
+    assert_eq!(report.items_with_id, 1);
+    assert_eq!(report.items_with_date, 1);
+    assert_eq!(
+        report.problems,
+        vec![
+            Problem::EmptyTitle(1),
+            Problem::RelativeLink(1),
+            Problem::MissingGuid(1),
+        ]
+    );
+    assert_eq!(report.error, None);
+}
+
+#[test]
+fn test_validate_broken_feed() {
+    let report = validate(b"<feed><entry><title>a</link></entry></feed>");
+    assert_eq!(report.format, FeedFormat::Atom);
+    assert!(report.error.is_some());
+}
+
+#[test]
+fn test_detect_unstable_ids() {
+    let feed = |ids: &[&str]| {
+        feed::RSS {
+            items: ids.iter()
+                .enumerate()
+                .map(|(i, id)| {
+                    feed::Item {
+                        title: Some(format!("title {}", i)),
+                        link: Some(format!("http://example.com/{}", i)),
+                        id: Some(id.to_string()),
+                        ..Default::default()
+                    }
+                })
+                .collect(),
+            ..Default::default()
+        }
+    };
+    let prev = feed(&["1500000000-0", "1500000000-1", "1500000000-2"]);
+    let curr = feed(&["1500000300-0", "1500000300-1", "1500000300-2"]);
+    assert!(detect_unstable_ids(&prev, &curr));
+
+    // a normal update, one new item
+    let curr = feed(&["1500000000-new", "1500000000-0", "1500000000-1"]);
+    assert!(!detect_unstable_ids(&prev, &curr));
+
+    // everything changed
+    let mut curr = feed(&["a", "b", "c"]);
+    for item in &mut curr.items {
+        item.title = Some("other".to_owned());
+    }
+    assert!(!detect_unstable_ids(&prev, &curr));
+}
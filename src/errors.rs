@@ -8,6 +8,44 @@ error_chain! {
             description("unexpected EOF")
         }
 
+        EmptyResponse {
+            description("empty response")
+        }
+
+        RateLimited(retry_after: ::std::time::Duration) {
+            description("rate limited")
+            display("HTTP 429 (Too Many Requests), retry after {}s", retry_after.as_secs())
+        }
+
+        // HTTP 410, the feed won't come back
+        Gone {
+            description("feed is gone")
+            display("HTTP 410 (Gone)")
+        }
+
+        Http3Unsupported {
+            description("HTTP/3 is not supported")
+        }
+
+        // `ParseOptions::deadline` passed
+        ParseTimeout {
+            description("parsing the feed took too long")
+        }
+
+        FeedNotFound {
+            description("no feed found in the page")
+        }
+
+        ChallengeRequired {
+            description("blocked by a Cloudflare challenge")
+        }
+
+        // the challenge came back although a cookie was sent, it expired or
+        // doesn't match the user agent
+        ChallengeRejected {
+            description("Cloudflare challenge cookie rejected")
+        }
+
         Http(code: u32) {
             description("unexpected HTTP response code")
             display("HTTP {} ({})", code, response_code(*code).unwrap_or("Unknown"))
@@ -33,6 +71,7 @@ error_chain! {
     foreign_links {
         Curl(::tokio_curl::PerformError);
         Utf8(::std::str::Utf8Error);
+        Io(::std::io::Error);
     }
 }
 
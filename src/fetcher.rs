@@ -1,20 +1,32 @@
-use std::time::Duration;
+use std::time::{Duration, Instant};
 use std::collections::HashMap;
+use std::rc::Rc;
+use std::cell::RefCell;
 
 use telebot;
 use telebot::functions::*;
 use tokio_core::reactor::{Interval, Handle, Timeout};
-use futures::{self, Future, Stream, IntoFuture};
+use futures::{self, Future, Stream};
 use tokio_curl::Session;
 use regex::Regex;
 
+use errors;
 use data;
 use feed;
+use pipeline::{CleanTitle, Pipeline, StripTracking};
 use utlis::{Escape, EscapeUrl, send_multiple_messages, format_and_split_msgs,
-            to_chinese_error_msg, truncate_message, chat_is_unavailable, TELEGRAM_MAX_MSG_LEN};
+            to_chinese_error_msg, truncate_title, chat_is_unavailable, TELEGRAM_MAX_MSG_LEN};
 
 // 5 minute
 const FREQUENCY_SECOND: u64 = 300;
+// a feed's ttl can't delay its next poll by more than a day
+const MAX_TTL_SECOND: u64 = 24 * 60 * 60;
+
+// hosts asked us to slow down (HTTP 429), until the `Instant`
+type PausedHosts = Rc<RefCell<HashMap<String, Instant>>>;
+
+// the loop tick of the last successful poll of a feed, and its ttl
+type PollTimes = Rc<RefCell<HashMap<String, (Instant, Option<Duration>)>>>;
 
 lazy_static!{
     // it's different from `feed::HOST`, so maybe need a better name?
@@ -22,25 +34,46 @@ lazy_static!{
 }
 
 pub fn spawn_fetcher(bot: telebot::RcBot, db: data::Database, handle: Handle) {
+    let paused_hosts: PausedHosts = Rc::new(RefCell::new(HashMap::new()));
+    let poll_times: PollTimes = Rc::new(RefCell::new(HashMap::new()));
     handle.clone().spawn(
         Interval::new(Duration::from_secs(FREQUENCY_SECOND), &handle)
             .expect("failed to start feed loop")
             .map_err(|e| error!("feed loop error: {}", e))
             .for_each(move |_| {
+                // ticks are exactly `FREQUENCY_SECOND` apart, unlike the fetches
+                let tick = Instant::now();
                 let feeds = db.get_all_feeds();
                 let grouped_feeds = grouping_by_host(feeds);
                 let handle2 = handle.clone();
                 let bot = bot.clone();
                 let db = db.clone();
+                let paused_hosts = paused_hosts.clone();
+                let poll_times = poll_times.clone();
                 let fetcher = futures::stream::iter(grouped_feeds.into_iter().map(Ok))
                     .for_each(move |group| {
                         let session = Session::new(handle2.clone());
                         let bot = bot.clone();
                         let db = db.clone();
+                        let paused_hosts = paused_hosts.clone();
+                        let poll_times = poll_times.clone();
                         let group_fetcher = futures::stream::iter(group.into_iter().map(Ok))
                             .for_each(move |feed| {
-                                fetch_feed_updates(bot.clone(), db.clone(), &session, feed)
-                                    .then(|_| Ok(()))
+                                if host_is_paused(&paused_hosts, &feed.link) ||
+                                    !poll_is_due(&poll_times, &feed.link)
+                                {
+                                    return futures::future::Either::A(futures::future::ok(()));
+                                }
+                                let r = fetch_feed_updates(
+                                    bot.clone(),
+                                    db.clone(),
+                                    &session,
+                                    feed,
+                                    paused_hosts.clone(),
+                                    poll_times.clone(),
+                                    tick,
+                                ).then(|_| Ok(()));
+                                futures::future::Either::B(r)
                             });
                         handle2.spawn(group_fetcher);
                         Timeout::new(Duration::from_secs(1), &handle2)
@@ -53,6 +86,23 @@ pub fn spawn_fetcher(bot: telebot::RcBot, db: data::Database, handle: Handle) {
     )
 }
 
+// what `fetch_feed_updates` would send, for debugging missing notifications.
+// no bot command exposes it yet
+#[allow(dead_code)]
+pub fn dry_run<'a>(
+    session: &Session,
+    db: data::Database,
+    link: String,
+) -> impl Future<Item = Option<data::DryRunReport>, Error = errors::Error> + 'a {
+    feed::fetch_feed(session, link.clone()).map(move |rss| db.dry_run(&link, rss.items))
+}
+
+// only run on the new items, after `db.update`: changing links before it would
+// change the dedup keys of the stored items and resend them
+fn item_pipeline() -> Pipeline {
+    Pipeline::new().add(StripTracking).add(CleanTitle)
+}
+
 fn grouping_by_host(feeds: Vec<data::Feed>) -> Vec<Vec<data::Feed>> {
     let mut result = HashMap::new();
     for feed in feeds {
@@ -70,66 +120,153 @@ fn get_host(url: &str) -> &str {
     )
 }
 
+fn host_is_paused(paused_hosts: &PausedHosts, link: &str) -> bool {
+    let host = get_host(link);
+    let mut paused_hosts = paused_hosts.borrow_mut();
+    match paused_hosts.get(host).cloned() {
+        Some(until) if until > Instant::now() => true,
+        Some(_) => {
+            paused_hosts.remove(host);
+            false
+        }
+        None => false,
+    }
+}
+
+// feeds that failed or were never polled are always due
+fn poll_is_due(poll_times: &PollTimes, link: &str) -> bool {
+    match poll_times.borrow().get(link) {
+        Some(&(last_poll, ttl)) => {
+            let interval = Duration::from_secs(FREQUENCY_SECOND);
+            feed::should_poll(last_poll, ttl, interval, interval)
+        }
+        None => true,
+    }
+}
+
+// what the subscribers are told after a failed fetch
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Failure {
+    // HTTP 410, the feed is already removed
+    Gone,
+    // failed for 5 days in a row
+    Failing,
+}
+
+fn failure_to_report(
+    db: &data::Database,
+    feed: &data::Feed,
+    e: &errors::Error,
+    paused_hosts: &PausedHosts,
+) -> Option<Failure> {
+    match *e {
+        // removed right away, or the next tick would fetch and report it again
+        errors::Error(errors::ErrorKind::Gone, _) => {
+            return db.remove_feed(&feed.link).map(|_| Failure::Gone);
+        }
+        errors::Error(errors::ErrorKind::EmptyResponse, _) => return None,
+        errors::Error(errors::ErrorKind::RateLimited(ref retry_after), _) => {
+            paused_hosts.borrow_mut().insert(
+                get_host(&feed.link).to_owned(),
+                Instant::now() + *retry_after,
+            );
+            return None;
+        }
+        _ => (),
+    }
+    // 1440 * 5 minute = 5 days
+    if db.inc_error_count(&feed.link) > 1440 {
+        db.reset_error_count(&feed.link);
+        Some(Failure::Failing)
+    } else {
+        None
+    }
+}
+
 fn fetch_feed_updates<'a>(
     bot: telebot::RcBot,
     db: data::Database,
     session: &Session,
     feed: data::Feed,
+    paused_hosts: PausedHosts,
+    poll_times: PollTimes,
+    tick: Instant,
 ) -> impl Future<Item = (), Error = ()> + 'a {
     let bot_ = bot.clone();
     let db_ = db.clone();
     let feed_ = feed.clone();
-    feed::fetch_feed(session, feed.link.to_owned())
-        .map(move |rss| (bot_, db_, rss, feed_))
+    feed::fetch_feed_conditional(session, feed.link.to_owned(), &feed.validators)
+        .map(move |resp| (bot_, db_, resp, feed_))
         .or_else(move |e| {
-            // 1440 * 5 minute = 5 days
-            if db.inc_error_count(&feed.link) > 1440 {
-                Err((bot, db, feed))
-            } else {
-                Ok(())
-            }.into_future()
-                .or_else(|(bot, db, feed)| {
-                    db.reset_error_count(&feed.link);
-                    let err_msg = to_chinese_error_msg(e);
-                    let mut msgs = Vec::with_capacity(feed.subscribers.len());
-                    for &subscriber in &feed.subscribers {
-                        let m = bot.message(
-                            subscriber,
+            let failure = match failure_to_report(&db, &feed, &e, &paused_hosts) {
+                Some(failure) => failure,
+                None => return futures::future::Either::A(futures::future::err(())),
+            };
+            let err_msg = to_chinese_error_msg(e);
+            let mut msgs = Vec::with_capacity(feed.subscribers.len());
+            for &subscriber in &feed.subscribers {
+                let m = bot.message(
+                    subscriber,
+                    match failure {
+                        Failure::Gone => {
+                            format!(
+                                "《<a href=\"{}\">{}</a>》已经永久关闭 ({}), 已自动取消订阅",
+                                EscapeUrl(&feed.link),
+                                Escape(&feed.title),
+                                Escape(&err_msg)
+                            )
+                        }
+                        Failure::Failing => {
                             format!(
                                 "《<a href=\"{}\">{}</a>》\
-                                                     已经连续 5 天拉取出错 ({}),\
-                                                     可能已经关闭, 请取消订阅",
+                                 已经连续 5 天拉取出错 ({}),\
+                                 可能已经关闭, 请取消订阅",
                                 EscapeUrl(&feed.link),
                                 Escape(&feed.title),
                                 Escape(&err_msg)
-                            ),
-                        ).parse_mode("HTML")
-                            .disable_web_page_preview(true)
-                            .send();
-                        let db = db.clone();
-                        let r = m.map_err(move |e| {
-                            match e {
-                                telebot::error::Error::Telegram(_, ref s, _)
-                                    if chat_is_unavailable(s) => {
-                                    db.delete_subscriber(subscriber);
-                                }
-                                _ => {
-                                    warn!("failed to send error to {}, {:?}", subscriber, e);
-                                }
-                            };
-                        });
-                        // if not use Box, rustc will panic
-                        msgs.push(Box::new(r) as Box<Future<Item = _, Error = _>>);
-                    }
-                    futures::future::join_all(msgs).then(|_| Err(()))
-                })
-                .and_then(|_| Err(()))
+                            )
+                        }
+                    },
+                ).parse_mode("HTML")
+                    .disable_web_page_preview(true)
+                    .send();
+                let db = db.clone();
+                let r = m.map_err(move |e| {
+                    match e {
+                        telebot::error::Error::Telegram(_, ref s, _) if chat_is_unavailable(s) => {
+                            db.delete_subscriber(subscriber);
+                        }
+                        _ => {
+                            warn!("failed to send error to {}, {:?}", subscriber, e);
+                        }
+                    };
+                });
+                // if not use Box, rustc will panic
+                msgs.push(Box::new(r) as Box<Future<Item = _, Error = _>>);
+            }
+            futures::future::Either::B(futures::future::join_all(msgs).then(|_| Err(())))
         })
-        .and_then(|(bot, db, rss, feed)| {
+        .and_then(move |(bot, db, resp, feed)| {
+            let (rss, validators) = match resp {
+                feed::Conditional::Modified(rss, validators) => (rss, validators),
+                feed::Conditional::NotModified => {
+                    db.reset_error_count(&feed.link);
+                    // the ttl of the last full response still applies
+                    let ttl = poll_times.borrow().get(&feed.link).and_then(|&(_, ttl)| ttl);
+                    poll_times.borrow_mut().insert(feed.link.clone(), (tick, ttl));
+                    return futures::future::err(());
+                }
+            };
+            let ttl = rss.ttl.map(|ttl| std::cmp::min(ttl, Duration::from_secs(MAX_TTL_SECOND)));
+            poll_times.borrow_mut().insert(feed.link.clone(), (tick, ttl));
+            if validators != feed.validators {
+                db.update_validators(&feed.link, validators);
+            }
             if rss.title != feed.title {
                 db.update_title(&feed.link, &rss.title);
             }
-            let updates = db.update(&feed.link, rss.items);
+            let mut updates = db.update(&feed.link, rss.items);
+            item_pipeline().apply(&mut updates);
             if updates.is_empty() {
                 futures::future::err(())
             } else {
@@ -148,7 +285,7 @@ fn fetch_feed_updates<'a>(
                     format!(
                         "<a href=\"{}\">{}</a>",
                         EscapeUrl(link),
-                        Escape(&truncate_message(title, TELEGRAM_MAX_MSG_LEN - 500))
+                        Escape(&truncate_title(title, TELEGRAM_MAX_MSG_LEN - 500))
                     )
                 });
 
@@ -171,3 +308,23 @@ fn fetch_feed_updates<'a>(
             futures::future::join_all(msg_futures).then(|_| Ok(()))
         })
 }
+
+#[test]
+fn test_gone_feed_reported_once() {
+    let path = std::env::temp_dir().join(format!("rssbot-test-gone-{}.json", std::process::id()));
+    let db = data::Database::create(path.to_str().unwrap()).unwrap();
+    let link = "http://example.com/feed";
+    db.subscribe(1, link, &feed::RSS::default()).unwrap();
+    db.subscribe(2, link, &feed::RSS::default()).unwrap();
+    let feed = db.get_all_feeds().pop().unwrap();
+    let paused_hosts: PausedHosts = Rc::new(RefCell::new(HashMap::new()));
+    let gone = || errors::Error::from(errors::ErrorKind::Gone);
+
+    assert_eq!(failure_to_report(&db, &feed, &gone(), &paused_hosts), Some(Failure::Gone));
+    // the next tick has nothing to fetch, and nothing to report if it did
+    assert!(db.get_all_feeds().is_empty());
+    assert!(db.get_subscribed_feeds(1).is_none());
+    assert!(db.get_subscribed_feeds(2).is_none());
+    assert_eq!(failure_to_report(&db, &feed, &gone(), &paused_hosts), None);
+    let _ = std::fs::remove_file(&path);
+}
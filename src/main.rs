@@ -1,4 +1,5 @@
 #![feature(conservative_impl_trait)]
+#![cfg_attr(test, feature(test))]
 
 #[macro_use]
 extern crate log;
@@ -18,6 +19,12 @@ extern crate telebot;
 extern crate lazy_static;
 extern crate regex;
 extern crate pinyin_order;
+extern crate chrono;
+extern crate flate2;
+#[cfg(feature = "zstd")]
+extern crate zstd;
+#[cfg(test)]
+extern crate test;
 
 use tokio_core::reactor::Core;
 use futures::Stream;
@@ -29,11 +36,39 @@ mod utlis;
 mod cmdhandels;
 mod fetcher;
 mod checker;
+mod diagnostics;
+mod pipeline;
+mod opml;
 
 fn main() {
     let args: Vec<String> = std::env::args().collect();
+    if args.len() >= 2 && args[1] == "parse" {
+        parse_stdin(args.get(2).map(|s| s.as_str()));
+        return;
+    }
+    if args.len() == 3 && args[1] == "validate" {
+        validate_url(&args[2]);
+        return;
+    }
+    if (args.len() == 3 || args.len() == 4) && args[1] == "export" {
+        export_opml(&args[2], args.get(3).map_or(false, |s| s == "--gzip"));
+        return;
+    }
+    if args.len() == 4 && args[1] == "import" {
+        import_opml(&args[2], &args[3]);
+        return;
+    }
+    if (args.len() == 4 || args.len() == 5) && args[1] == "keep-fragment" {
+        set_keep_fragment(&args[2], &args[3], args.get(4).map_or(true, |s| s != "--off"));
+        return;
+    }
     if args.len() < 3 {
         eprintln!("Usage: {} DATAFILE TELEGRAM-BOT-TOKEN", args[0]);
+        eprintln!("       {} parse [BASE-URL] < FEED", args[0]);
+        eprintln!("       {} validate URL", args[0]);
+        eprintln!("       {} export DATAFILE [--gzip] > OPML", args[0]);
+        eprintln!("       {} import DATAFILE CHAT-ID < OPML", args[0]);
+        eprintln!("       {} keep-fragment DATAFILE FEED-URL [--off]", args[0]);
         std::process::exit(1);
     }
     let datafile = &args[1];
@@ -75,3 +110,102 @@ fn main() {
         .for_each(|_| Ok(()));
     lp.run(s).unwrap();
 }
+
+// `curl -s URL | rssbot parse URL`, relative links are kept without a base URL
+fn parse_stdin(base_url: Option<&str>) {
+    let stdin = std::io::stdin();
+    let reader = stdin.lock();
+    let rss = match base_url {
+        Some(base_url) => feed::parse_and_fix(reader, base_url),
+        None => feed::parse(reader),
+    };
+    let rss = rss.unwrap_or_else(|e| {
+        eprintln!("error: {}", e);
+        std::process::exit(1);
+    });
+    println!("{}\n{}", rss.title, rss.link);
+    for item in &rss.items {
+        println!(
+            "{}\t{}",
+            item.title.as_ref().map_or("", |s| s.as_str()),
+            item.link.as_ref().map_or("", |s| s.as_str())
+        );
+    }
+}
+
+// `rssbot validate URL`, for finding out why a feed behaves oddly in the bot
+fn validate_url(link: &str) {
+    let mut lp = Core::new().unwrap();
+    let session = tokio_curl::Session::new(lp.handle());
+    let report = lp.run(diagnostics::validate_feed(&session, link))
+        .unwrap_or_else(|e| {
+            eprintln!("error: {}", e);
+            std::process::exit(1);
+        });
+    println!("format: {:?}", report.format);
+    println!("charset: {}", report.charset);
+    println!(
+        "items: {} ({} with link, {} with id, {} with date)",
+        report.item_count,
+        report.items_with_link,
+        report.items_with_id,
+        report.items_with_date
+    );
+    if let Some(ref error) = report.error {
+        println!("error: {}", error);
+    }
+    for problem in &report.problems {
+        println!("problem: {:?}", problem);
+    }
+}
+
+fn exit_with_error<T>(e: errors::Error) -> T {
+    eprintln!("error: {}", e);
+    std::process::exit(1);
+}
+
+// `rssbot export DATAFILE > subscriptions.opml`, every feed anyone subscribed to
+fn export_opml(datafile: &str, gzip: bool) {
+    let db = data::Database::open(datafile).unwrap_or_else(exit_with_error);
+    let feeds: Vec<_> = db.get_all_feeds()
+        .into_iter()
+        .map(|feed| {
+            opml::OpmlFeed {
+                title: feed.title,
+                xml_url: feed.link,
+                html_url: None,
+            }
+        })
+        .collect();
+    let stdout = std::io::stdout();
+    opml::write_opml(stdout.lock(), &feeds, gzip).unwrap_or_else(exit_with_error);
+}
+
+// `rssbot import DATAFILE CHAT-ID < subscriptions.opml`, plain or gzipped. every
+// feed is fetched like `/sub` does, the ones that fail are reported and skipped
+fn import_opml(datafile: &str, subscriber: &str) {
+    let subscriber = subscriber.parse::<i64>().unwrap_or_else(|_| {
+        eprintln!("error: invalid chat id: {}", subscriber);
+        std::process::exit(1);
+    });
+    let db = data::Database::open(datafile).unwrap_or_else(exit_with_error);
+    let stdin = std::io::stdin();
+    let feeds = opml::parse_opml(stdin.lock()).unwrap_or_else(exit_with_error);
+    let mut lp = Core::new().unwrap();
+    let session = tokio_curl::Session::new(lp.handle());
+    for feed in feeds {
+        let result = lp.run(feed::fetch_feed(&session, feed.xml_url.clone()))
+            .and_then(|rss| db.subscribe(subscriber, &feed.xml_url, &rss));
+        match result {
+            Ok(()) => println!("subscribed: {}", feed.xml_url),
+            Err(e) => eprintln!("failed: {}: {}", feed.xml_url, e),
+        }
+    }
+}
+
+// `rssbot keep-fragment DATAFILE URL`, for feeds whose items only differ in
+// the fragment of their links. `--off` strips it again
+fn set_keep_fragment(datafile: &str, rss_link: &str, keep_fragment: bool) {
+    let db = data::Database::open(datafile).unwrap_or_else(exit_with_error);
+    db.set_keep_fragment(rss_link, keep_fragment).unwrap_or_else(exit_with_error);
+}
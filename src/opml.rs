@@ -0,0 +1,158 @@
+use std::io::{BufRead, BufReader, Write};
+
+use flate2::Compression;
+use flate2::read::GzDecoder;
+use flate2::write::GzEncoder;
+use quick_xml::events::Event as XmlEvent;
+use quick_xml::events::attributes::Attributes;
+use quick_xml::reader::Reader as XmlReader;
+
+use errors::*;
+use feed::{sniff_feed_type, xml_escape, SniffResult};
+
+// an `<outline>` with an `xmlUrl`, categories (nested outlines) are flattened
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct OpmlFeed {
+    pub title: String,
+    pub xml_url: String,
+    pub html_url: Option<String>,
+}
+
+// plain or gzipped, told apart by the gzip magic
+pub fn parse_opml<B: BufRead>(mut reader: B) -> Result<Vec<OpmlFeed>> {
+    let gzipped = sniff_feed_type(reader.fill_buf()?) == SniffResult::Gzip;
+    if gzipped {
+        parse_outlines(BufReader::new(GzDecoder::new(reader)))
+    } else {
+        parse_outlines(reader)
+    }
+}
+
+fn parse_outlines<B: BufRead>(reader: B) -> Result<Vec<OpmlFeed>> {
+    let mut reader = XmlReader::from_reader(reader);
+    reader.trim_text(true);
+    let mut buf = Vec::new();
+    let mut feeds = Vec::new();
+    loop {
+        match reader.read_event(&mut buf) {
+            Ok(XmlEvent::Start(ref e)) |
+            Ok(XmlEvent::Empty(ref e)) => {
+                if e.name() == b"outline" {
+                    if let Some(feed) = parse_outline(&reader, e.attributes()) {
+                        feeds.push(feed);
+                    }
+                }
+            }
+            Ok(XmlEvent::Eof) => break,
+            Err(err) => return Err(err.into()),
+            _ => (),
+        }
+        buf.clear();
+    }
+    Ok(feeds)
+}
+
+fn parse_outline<B: BufRead>(reader: &XmlReader<B>, attributes: Attributes) -> Option<OpmlFeed> {
+    let mut feed = OpmlFeed::default();
+    let mut text = String::new();
+    for attribute in attributes {
+        if let Ok(attribute) = attribute {
+            let value = match attribute.unescape_and_decode_value(reader) {
+                Ok(value) => value,
+                Err(_) => continue,
+            };
+            match reader.decode(attribute.key).as_ref() {
+                "xmlUrl" => feed.xml_url = value,
+                "htmlUrl" => feed.html_url = Some(value),
+                "title" => feed.title = value,
+                "text" => text = value,
+                _ => (),
+            }
+        }
+    }
+    if feed.xml_url.trim().is_empty() {
+        return None;
+    }
+    if feed.title.is_empty() {
+        feed.title = text;
+    }
+    Some(feed)
+}
+
+pub fn write_opml<W: Write>(out: W, feeds: &[OpmlFeed], gzip: bool) -> Result<()> {
+    if gzip {
+        let mut encoder = GzEncoder::new(out, Compression::default());
+        write_outlines(&mut encoder, feeds)?;
+        encoder.finish()?;
+        Ok(())
+    } else {
+        write_outlines(out, feeds)
+    }
+}
+
+fn write_outlines<W: Write>(mut out: W, feeds: &[OpmlFeed]) -> Result<()> {
+    writeln!(out, "<?xml version=\"1.0\" encoding=\"utf-8\"?>")?;
+    writeln!(out, "<opml version=\"2.0\">")?;
+    writeln!(out, "<head><title>rssbot subscriptions</title></head>")?;
+    writeln!(out, "<body>")?;
+    for feed in feeds {
+        let title = xml_escape(&feed.title);
+        write!(
+            out,
+            "<outline type=\"rss\" text=\"{}\" title=\"{}\" xmlUrl=\"{}\"",
+            title,
+            title,
+            xml_escape(&feed.xml_url)
+        )?;
+        if let Some(ref html_url) = feed.html_url {
+            write!(out, " htmlUrl=\"{}\"", xml_escape(html_url))?;
+        }
+        writeln!(out, "/>")?;
+    }
+    writeln!(out, "</body>")?;
+    writeln!(out, "</opml>")?;
+    Ok(())
+}
+
+#[test]
+fn test_opml_round_trip() {
+    let feeds = vec![
+        OpmlFeed {
+            title: "A & B".to_owned(),
+            xml_url: "http://example.com/feed?a=1&b=2".to_owned(),
+            html_url: Some("http://example.com/".to_owned()),
+        },
+        OpmlFeed {
+            title: "中文".to_owned(),
+            xml_url: "http://example.org/rss".to_owned(),
+            html_url: None,
+        },
+    ];
+    for &gzip in &[true, false] {
+        let mut out = Vec::new();
+        write_opml(&mut out, &feeds, gzip).unwrap();
+        assert_eq!(sniff_feed_type(&out) == SniffResult::Gzip, gzip);
+        assert_eq!(parse_opml(out.as_slice()).unwrap(), feeds);
+    }
+}
+
+#[test]
+fn test_parse_opml() {
+    let opml = br#"<opml version="1.0"><body>
+        <outline text="Tech">
+            <outline text="Example" type="rss" xmlUrl="http://example.com/feed"/>
+        </outline>
+        <outline text="not a feed"/>
+        </body></opml>"#;
+    let feeds = parse_opml(&opml[..]).unwrap();
+    assert_eq!(
+        feeds,
+        vec![
+            OpmlFeed {
+                title: "Example".to_owned(),
+                xml_url: "http://example.com/feed".to_owned(),
+                html_url: None,
+            },
+        ]
+    );
+}
@@ -0,0 +1,156 @@
+use feed::{self, Item};
+use utlis::truncate_title;
+
+// one step of the item post-processing, run in the order they're added
+pub trait ItemProcessor {
+    fn process(&self, item: &mut Item);
+}
+
+impl<F: Fn(&mut Item)> ItemProcessor for F {
+    fn process(&self, item: &mut Item) {
+        self(item)
+    }
+}
+
+#[derive(Default)]
+pub struct Pipeline {
+    processors: Vec<Box<ItemProcessor>>,
+}
+
+impl Pipeline {
+    pub fn new() -> Pipeline {
+        Pipeline::default()
+    }
+
+    pub fn add<P: ItemProcessor + 'static>(mut self, processor: P) -> Pipeline {
+        self.processors.push(Box::new(processor));
+        self
+    }
+
+    pub fn process(&self, item: &mut Item) {
+        for processor in &self.processors {
+            processor.process(item);
+        }
+    }
+
+    pub fn apply(&self, items: &mut [Item]) {
+        for item in items {
+            self.process(item);
+        }
+    }
+}
+
+// `utm_*` and the click ids of ad networks
+pub struct StripTracking;
+
+const TRACKING_PARAMS: &[&str] = &["fbclid", "gclid", "mc_cid", "mc_eid"];
+
+impl ItemProcessor for StripTracking {
+    fn process(&self, item: &mut Item) {
+        if let Some(link) = item.link.as_mut() {
+            *link = strip_tracking_params(link);
+        }
+    }
+}
+
+fn strip_tracking_params(link: &str) -> String {
+    let (link, fragment) = match link.find('#') {
+        Some(i) => link.split_at(i),
+        None => (link, ""),
+    };
+    let (base, query) = match link.find('?') {
+        Some(i) => (&link[..i], &link[i + 1..]),
+        None => return format!("{}{}", link, fragment),
+    };
+    let params: Vec<&str> = query
+        .split('&')
+        .filter(|param| {
+            let key = param.split('=').next().unwrap_or_default();
+            !param.is_empty() && !key.starts_with("utm_") && !TRACKING_PARAMS.contains(&key)
+        })
+        .collect();
+    if params.is_empty() {
+        format!("{}{}", base, fragment)
+    } else {
+        format!("{}?{}{}", base, params.join("&"), fragment)
+    }
+}
+
+// `None` from the function keeps the link, see `RSS::rewrite_links`.
+// there are no per-feed rewrite rules for the fetcher to use yet
+#[allow(dead_code)]
+pub struct RewriteLinks<F>(pub F);
+
+impl<F: Fn(&str) -> Option<String>> ItemProcessor for RewriteLinks<F> {
+    fn process(&self, item: &mut Item) {
+        if let Some(link) = item.link.as_ref().and_then(|link| (self.0)(link)) {
+            item.link = Some(link);
+        }
+    }
+}
+
+// markup and runs of whitespace in titles
+pub struct CleanTitle;
+
+impl ItemProcessor for CleanTitle {
+    fn process(&self, item: &mut Item) {
+        if let Some(title) = item.title.as_mut() {
+            *title = feed::strip_html(title).split_whitespace().collect::<Vec<_>>().join(" ");
+        }
+    }
+}
+
+// not run by the fetcher, the message formatting also truncates the feed title
+#[allow(dead_code)]
+pub struct TruncateTitle(pub usize);
+
+impl ItemProcessor for TruncateTitle {
+    fn process(&self, item: &mut Item) {
+        if let Some(title) = item.title.as_mut() {
+            *title = truncate_title(title, self.0);
+        }
+    }
+}
+
+#[test]
+fn test_strip_tracking_params() {
+    assert_eq!(
+        strip_tracking_params("http://example.com/a?utm_source=rss&id=1&fbclid=x#top"),
+        "http://example.com/a?id=1#top"
+    );
+    assert_eq!(
+        strip_tracking_params("http://example.com/a?utm_medium=feed"),
+        "http://example.com/a"
+    );
+    assert_eq!(strip_tracking_params("http://example.com/a"), "http://example.com/a");
+}
+
+#[test]
+fn test_pipeline() {
+    let mut items = vec![
+        Item {
+            title: Some("  <b>Big</b>   news\ttoday ".to_owned()),
+            link: Some("https://www.youtube.com/watch?v=abc&utm_source=rss".to_owned()),
+            ..Default::default()
+        },
+    ];
+    let pipeline = Pipeline::new()
+        .add(StripTracking)
+        .add(RewriteLinks(|link: &str| if link.starts_with("https://www.youtube.com/") {
+            Some(link.replace("www.youtube.com", "invidious.example.org"))
+        } else {
+            None
+        }))
+        .add(CleanTitle)
+        .add(TruncateTitle(9))
+        // sees the results of the ones before
+        .add(|item: &mut Item| if let Some(title) = item.title.as_mut() {
+            title.push_str(" [yt]");
+        });
+    pipeline.apply(&mut items);
+    assert_eq!(items[0].title, Some("Big news… [yt]".to_owned()));
+    assert_eq!(
+        items[0].link,
+        Some("https://invidious.example.org/watch?v=abc".to_owned())
+    );
+}
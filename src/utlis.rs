@@ -3,6 +3,7 @@ use telebot::functions::*;
 use futures::{self, Future, Stream};
 
 use errors;
+use feed::is_cjk;
 
 pub const TELEGRAM_MAX_MSG_LEN: usize = 4096;
 
@@ -95,6 +96,46 @@ pub fn truncate_message(s: &str, max: usize) -> String {
     }
 }
 
+// cuts between words where possible, CJK text has no spaces so it's cut
+// anywhere except before a combining mark
+pub fn truncate_title(title: &str, max_chars: usize) -> String {
+    let title = title.trim();
+    if title.chars().count() <= max_chars {
+        return title.to_owned();
+    }
+    if max_chars == 0 {
+        return String::new();
+    }
+    // one char is left for the ellipsis
+    let mut end = title.char_indices().nth(max_chars - 1).unwrap().0;
+    while end > 0 && is_combining(title[end..].chars().next().unwrap()) {
+        end = title[..end].char_indices().next_back().unwrap().0;
+    }
+    let mut cut = &title[..end];
+    let in_word = |c: char| c.is_alphanumeric() && !is_cjk(c);
+    let splits_word = cut.chars().next_back().map_or(false, &in_word) &&
+        title[end..].chars().next().map_or(false, &in_word);
+    if splits_word {
+        if let Some(i) = cut.rfind(char::is_whitespace) {
+            // unless that loses too much
+            if cut[..i].chars().count() >= max_chars * 2 / 3 {
+                cut = &cut[..i];
+            }
+        }
+    }
+    format!("{}…", cut.trim_right())
+}
+
+fn is_combining(c: char) -> bool {
+    match c {
+        '\u{300}'...'\u{36f}' |
+        '\u{200c}'...'\u{200d}' | // ZWNJ, ZWJ
+        '\u{fe00}'...'\u{fe0f}' | // variation selectors
+        '\u{1f3fb}'...'\u{1f3ff}' => true, // skin tones
+        _ => false,
+    }
+}
+
 pub fn format_and_split_msgs<T, F>(head: String, data: &[T], line_format_fn: F) -> Vec<String>
 where
     F: Fn(&T) -> String,
@@ -142,3 +183,288 @@ pub fn log_error(e: &errors::Error) {
         warn!("backtrace: {:?}", backtrace);
     }
 }
+
+// messages only carry item titles so far, this is for when they include descriptions
+#[allow(dead_code)]
+pub fn sanitize_html_for_telegram(html: &str) -> String {
+    sanitize_html(html, TELEGRAM_MAX_MSG_LEN)
+}
+
+// https://core.telegram.org/bots/api#html-style
+// tags must not be nested, so only one allowed tag can be open at a time
+fn sanitize_html(html: &str, max_len: usize) -> String {
+    let mut result = String::with_capacity(html.len());
+    let mut open_tag: Option<&'static str> = None;
+    let mut len = 0;
+    let mut rest = html;
+    'outer: while !rest.is_empty() {
+        if rest.starts_with('<') {
+            if let Some(end) = rest.find('>') {
+                let tag = &rest[1..end];
+                rest = &rest[end + 1..];
+                let closing = tag.starts_with('/');
+                let tag = tag.trim_left_matches('/');
+                let name = tag.split(|c: char| c.is_whitespace() || c == '/')
+                    .next()
+                    .unwrap_or_default()
+                    .to_lowercase();
+                match name.as_str() {
+                    "script" | "style" if !closing => {
+                        let close = format!("</{}", name);
+                        rest = match rest.to_ascii_lowercase().find(&close) {
+                            Some(i) => {
+                                let end = rest[i..].find('>').map_or(rest.len(), |j| i + j + 1);
+                                &rest[end..]
+                            }
+                            None => "",
+                        };
+                    }
+                    "br" | "p" | "div" | "li" | "tr" if !result.is_empty() &&
+                                                       !result.ends_with('\n') => {
+                        if len + 1 > max_len {
+                            break 'outer;
+                        }
+                        result.push('\n');
+                        len += 1;
+                    }
+                    "b" | "strong" | "i" | "em" | "a" | "code" | "pre" => {
+                        let allowed = match name.as_str() {
+                            "b" | "strong" => "b",
+                            "i" | "em" => "i",
+                            "a" => "a",
+                            "code" => "code",
+                            _ => "pre",
+                        };
+                        if closing {
+                            if open_tag == Some(allowed) {
+                                result.push_str("</");
+                                result.push_str(allowed);
+                                result.push('>');
+                                open_tag = None;
+                            }
+                        } else if open_tag.is_none() {
+                            if allowed == "a" {
+                                // anything else, `javascript:` for one, unwraps the link
+                                let href = html_attribute(tag, "href")
+                                    .map(|href| href.trim())
+                                    .and_then(|href| if is_allowed_href(href) {
+                                        Some(href)
+                                    } else {
+                                        None
+                                    });
+                                if let Some(href) = href {
+                                    result.push_str(&format!("<a href=\"{}\">", EscapeUrl(href)));
+                                    open_tag = Some(allowed);
+                                }
+                            } else {
+                                result.push('<');
+                                result.push_str(allowed);
+                                result.push('>');
+                                open_tag = Some(allowed);
+                            }
+                        }
+                    }
+                    _ => (),
+                }
+                continue;
+            }
+        }
+        let next = rest.char_indices()
+            .skip(1)
+            .find(|&(_, c)| c == '<')
+            .map_or(rest.len(), |(i, _)| i);
+        let text = &rest[..next];
+        rest = &rest[next..];
+        let mut chars = text.char_indices();
+        while let Some((i, ch)) = chars.next() {
+            if len + 1 > max_len.saturating_sub(3) {
+                result.push_str("...");
+                break 'outer;
+            }
+            len += 1;
+            match ch {
+                '<' => result.push_str("&lt;"),
+                '>' => result.push_str("&gt;"),
+                '"' => result.push_str("&quot;"),
+                '&' => {
+                    let entity = text[i..].find(';').map(|j| &text[i..i + j + 1]).and_then(
+                        |e| if e.len() <= 10 &&
+                            e[1..e.len() - 1].chars().all(
+                                |c| c.is_alphanumeric() || c == '#',
+                            )
+                        {
+                            Some(e)
+                        } else {
+                            None
+                        },
+                    );
+                    match entity {
+                        Some(e) if e.starts_with("&#") || e == "&lt;" || e == "&gt;" ||
+                                       e == "&amp;" || e == "&quot;" => {
+                            result.push_str(e);
+                            for _ in 1..e.chars().count() {
+                                chars.next();
+                            }
+                        }
+                        Some("&nbsp;") => {
+                            result.push(' ');
+                            for _ in 1.."&nbsp;".len() {
+                                chars.next();
+                            }
+                        }
+                        _ => result.push_str("&amp;"),
+                    }
+                }
+                _ => result.push(ch),
+            }
+        }
+    }
+    while result.ends_with('\n') {
+        result.pop();
+    }
+    if let Some(tag) = open_tag {
+        result.push_str("</");
+        result.push_str(tag);
+        result.push('>');
+    }
+    result
+}
+
+fn is_allowed_href(href: &str) -> bool {
+    const SCHEMES: &[&str] = &["http://", "https://", "tg://", "mailto:"];
+    let href = href.to_ascii_lowercase();
+    SCHEMES.iter().any(|scheme| href.starts_with(scheme))
+}
+
+pub fn html_attribute<'a>(tag: &'a str, name: &str) -> Option<&'a str> {
+    let lower = tag.to_ascii_lowercase();
+    // `name=` inside the quoted value of another attribute doesn't count
+    let mut quote = None;
+    let mut after_equals = false;
+    for (i, c) in lower.char_indices() {
+        if let Some(q) = quote {
+            if c == q {
+                quote = None;
+            }
+            continue;
+        }
+        match c {
+            '"' | '\'' if after_equals => {
+                quote = Some(c);
+                after_equals = false;
+            }
+            '=' => after_equals = true,
+            c if c.is_whitespace() => {
+                let from = i + c.len_utf8();
+                if after_equals || !lower[from..].starts_with(name) {
+                    continue;
+                }
+                let value = lower[from + name.len()..].trim_left();
+                if value.starts_with('=') {
+                    let value_start = tag.len() - value.len() + 1;
+                    return Some(attribute_value(tag[value_start..].trim_left()));
+                }
+            }
+            _ => after_equals = false,
+        }
+    }
+    None
+}
+
+fn attribute_value(value: &str) -> &str {
+    match value.chars().next() {
+        Some(quote @ '"') | Some(quote @ '\'') => {
+            let value = &value[1..];
+            &value[..value.find(quote).unwrap_or_else(|| value.len())]
+        }
+        _ => {
+            let end = value.find(|c: char| c.is_whitespace()).unwrap_or_else(|| value.len());
+            &value[..end]
+        }
+    }
+}
+
+#[test]
+fn test_sanitize_html_strips_disallowed_tags() {
+    assert_eq!(
+        sanitize_html_for_telegram(
+            "<div class=\"x\"><span>Hello</span> <b>world</b><script>alert(1)</script></div>",
+        ),
+        "Hello <b>world</b>"
+    );
+    assert_eq!(
+        sanitize_html_for_telegram("<p>a</p><p>b &nbsp;&copy; &amp; 1 > 0</p>"),
+        "a\nb  &amp;copy; &amp; 1 &gt; 0"
+    );
+    // nested tags are unwrapped
+    assert_eq!(
+        sanitize_html_for_telegram("<b><i>bold</i></b>"),
+        "<b>bold</b>"
+    );
+}
+
+#[test]
+fn test_sanitize_html_preserves_links() {
+    assert_eq!(
+        sanitize_html_for_telegram(
+            "see <a class=\"ext\" href='http://example.com/?a=1&amp;b=2'>this</a>",
+        ),
+        "see <a href=\"http://example.com/?a=1&amp;b=2\">this</a>"
+    );
+    assert_eq!(sanitize_html_for_telegram("<a name=\"top\">top</a>"), "top");
+    assert_eq!(
+        sanitize_html_for_telegram("<a href=\"tg://resolve?domain=x\">x</a>"),
+        "<a href=\"tg://resolve?domain=x\">x</a>"
+    );
+}
+
+#[test]
+fn test_sanitize_html_unwraps_other_schemes() {
+    assert_eq!(
+        sanitize_html_for_telegram("<a href=\"javascript:alert(1)\">click</a> me"),
+        "click me"
+    );
+    assert_eq!(sanitize_html_for_telegram("<a href=' JavaScript:x'>a</a>"), "a");
+    assert_eq!(sanitize_html_for_telegram("<a href=\"data:text/html,x\">a</a>"), "a");
+    assert_eq!(sanitize_html_for_telegram("<a href=\"/relative\">a</a>"), "a");
+    assert_eq!(
+        sanitize_html_for_telegram("<a href=\"MAILTO:a@example.com\">a</a>"),
+        "<a href=\"MAILTO:a@example.com\">a</a>"
+    );
+}
+
+#[test]
+fn test_html_attribute() {
+    assert_eq!(html_attribute("a href=\"x\"", "href"), Some("x"));
+    assert_eq!(html_attribute("a HREF = 'x' rel=y", "rel"), Some("y"));
+    assert_eq!(html_attribute("a data-href=\"x\"", "href"), None);
+    // inside the quoted value of another attribute
+    assert_eq!(
+        html_attribute("a title=\"see href=javascript:x\" href=\"http://example.com/\"", "href"),
+        Some("http://example.com/")
+    );
+    assert_eq!(html_attribute("a title='x href=y'", "href"), None);
+    assert_eq!(html_attribute("a title=x href=y", "href"), Some("y"));
+}
+
+#[test]
+fn test_sanitize_html_truncates() {
+    let html = format!("<b>{}</b>", "a".repeat(TELEGRAM_MAX_MSG_LEN));
+    let result = sanitize_html_for_telegram(&html);
+    assert!(result.starts_with("<b>aaa"));
+    assert!(result.ends_with("...</b>"));
+    assert_eq!(result.len(), TELEGRAM_MAX_MSG_LEN + "<b></b>".len());
+}
+
+#[test]
+fn test_truncate_title() {
+    assert_eq!(truncate_title(" short ", 10), "short");
+    assert_eq!(truncate_title("hello wonderful world", 18), "hello wonderful…");
+    // a single long word is cut anyway
+    assert_eq!(truncate_title("abcdefghij", 5), "abcd…");
+    assert_eq!(truncate_title("中文标题不会有空格", 5), "中文标题…");
+    assert_eq!(truncate_title("😀😀😀😀", 3), "😀😀…");
+    // "e" + combining acute accent stays together
+    assert_eq!(truncate_title("cafe\u{301}s", 5), "caf…");
+    assert_eq!(truncate_title("abc", 0), "");
+}